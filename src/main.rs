@@ -25,7 +25,7 @@ use std::thread;
 use std::time;
 
 mod server;
-use server::{HttpConnection, HttpTui};
+use server::{parse_proxy_rule, HttpConnection, HttpTui};
 
 use std::net::SocketAddr;
 
@@ -40,6 +40,22 @@ struct Opts {
     port: u16,
     #[clap(short, long, default_value = "127.0.0.1")]
     host: String,
+    #[clap(long, default_value = "8")]
+    max_connections_per_ip: usize,
+    #[clap(long, default_value = "0")]
+    rate_limit: u64,
+    #[clap(long)]
+    global_rate_limit: bool,
+    #[clap(long)]
+    metrics: bool,
+    #[clap(long)]
+    tls: bool,
+    #[clap(long)]
+    cert: Option<String>,
+    #[clap(long)]
+    key: Option<String>,
+    #[clap(long)]
+    proxy: Vec<String>,
 }
 
 struct ConnectionSpeedMeasurement {
@@ -74,6 +90,7 @@ struct Connection {
     prev_update_time: time::Instant,
     avg_speed: ConnectionSpeedMeasurement,
     last_requested_uri: String,
+    ip_connection_count: usize,
 }
 
 impl Connection {
@@ -87,15 +104,17 @@ impl Connection {
             prev_update_time: time::Instant::now(),
             avg_speed: ConnectionSpeedMeasurement::new(),
             last_requested_uri: "[Reading...]".to_string(),
+            ip_connection_count: 1,
         }
     }
 
-    pub fn update(&mut self, conn: &HttpConnection) {
+    pub fn update(&mut self, conn: &HttpConnection, ip_connection_count: usize) {
         self.bytes_sent = conn.bytes_sent;
         self.bytes_requested = conn.bytes_requested;
         if let Some(uri) = &conn.last_requested_uri {
             self.last_requested_uri = uri.clone();
         }
+        self.ip_connection_count = ip_connection_count;
     }
 
     pub fn estimated_speed(&mut self) -> f32 {
@@ -125,7 +144,7 @@ impl ConnectionSet {
         }
     }
 
-    pub fn update(&mut self, current_conns: &HashMap<i32, HttpConnection>) {
+    pub fn update(&mut self, current_conns: &HashMap<i32, HttpConnection>, conns_per_ip: &HashMap<std::net::IpAddr, usize>) {
         let mut reindexed = HashMap::<SocketAddr, &HttpConnection>::new();
         for (_, conn) in current_conns {
             let peer_addr = match conn.stream.peer_addr() {
@@ -147,9 +166,10 @@ impl ConnectionSet {
         }
 
         for (addr, conn) in reindexed {
+            let ip_connection_count = conns_per_ip.get(&addr.ip()).copied().unwrap_or(1);
             self.connections.entry(addr)
                 .or_insert(Connection::new(addr))
-                .update(conn);
+                .update(conn, ip_connection_count);
         }
     }
 }
@@ -169,13 +189,39 @@ fn main() -> Result<(), io::Error> {
         }
     };
     let mut tui = match HttpTui::new(&opts.host, opts.port, &canon_path.as_path()) {
-        Ok(tui) => tui,
+        Ok(tui) => tui
+            .with_max_connections_per_ip(opts.max_connections_per_ip)
+            .with_rate_limit(opts.rate_limit, opts.global_rate_limit)
+            .with_metrics_enabled(opts.metrics),
         Err(e) => {
             eprintln!("Failed to bind to port {}: {}", opts.port, e);
             return Ok(());
         }
     };
 
+    for spec in &opts.proxy {
+        match parse_proxy_rule(spec) {
+            Some(rule) => { tui = tui.with_proxy_rule(rule); }
+            None => eprintln!("Ignoring malformed --proxy value: {}", spec),
+        }
+    }
+
+    let mut tui = if opts.tls {
+        let cert_path = opts.cert.as_ref().map(Path::new);
+        let key_path = opts.key.as_ref().map(Path::new);
+        match tui.with_tls(cert_path, key_path) {
+            Ok(tui) => tui,
+            Err(e) => {
+                eprintln!("Failed to configure TLS: {}", e);
+                return Ok(());
+            }
+        }
+    } else {
+        tui
+    };
+
+    let tls_fingerprint = tui.tls_fingerprint().map(|s| s.to_string());
+
     let connection_set = Arc::new(Mutex::new(ConnectionSet::new()));
     let connection_set_needs_update = Arc::new(AtomicBool::new(false));
 
@@ -194,7 +240,7 @@ fn main() -> Result<(), io::Error> {
     let connection_set_ptr = connection_set.clone();
     let canon_path = canon_path.clone();
     let thd = thread::spawn(move || {
-        let _ = display(canon_path.display(), connection_set_ptr, rx, &needs_update_clone);
+        let _ = display(canon_path.display(), tls_fingerprint, connection_set_ptr, rx, &needs_update_clone);
         let _ = unistd::write(write_end, "\0".as_bytes());
         let _ = unistd::close(write_end);
     });
@@ -211,9 +257,9 @@ fn main() -> Result<(), io::Error> {
         }
     });
 
-    tui.run(read_end, move |connections| {
+    tui.run(read_end, move |connections, conns_per_ip| {
         if connection_set_needs_update.swap(false, Ordering::Relaxed) {
-            connection_set.lock().unwrap().update(&connections);
+            connection_set.lock().unwrap().update(&connections, conns_per_ip);
         }
     });
 
@@ -232,16 +278,18 @@ fn build_str(addr: &SocketAddr, conn: &mut Connection) -> String {
     let speed = conn.estimated_speed();
     let ip_str = match addr {
         SocketAddr::V4(v4_addr) => {
-            format!("{host}:{port} {uri} => {sent}/{reqd}\r\n\t >> ({perc}% {speed} MiB/s)",
+            format!("{host}:{port} [{ip_count} from this IP] {uri} => {sent}/{reqd}\r\n\t >> ({perc}% {speed} MiB/s)",
                     host=v4_addr.ip(), port=v4_addr.port(),
+                    ip_count=conn.ip_connection_count,
                     uri=conn.last_requested_uri,
                     sent=conn.bytes_sent, reqd=conn.bytes_requested,
                     perc=perc,
                     speed=speed / (1024. * 1024.))
         }
         SocketAddr::V6(v6_addr) => {
-            format!("[{host}:{port}] {uri} => {sent}/{reqd}\r\n\t >> ({perc}% {speed} MiB/s)",
+            format!("[{host}:{port}] [{ip_count} from this IP] {uri} => {sent}/{reqd}\r\n\t >> ({perc}% {speed} MiB/s)",
                     host=v6_addr.ip(), port=v6_addr.port(),
+                    ip_count=conn.ip_connection_count,
                     uri=conn.last_requested_uri,
                     sent=conn.bytes_sent, reqd=conn.bytes_requested,
                     perc=perc,
@@ -252,7 +300,7 @@ fn build_str(addr: &SocketAddr, conn: &mut Connection) -> String {
     ip_str
 }
 
-fn display(root_path: Display, connection_set: Arc<Mutex<ConnectionSet>>, rx: mpsc::Receiver<ControlEvent>, needs_update: &AtomicBool) -> Result<(), io::Error> {
+fn display(root_path: Display, tls_fingerprint: Option<String>, connection_set: Arc<Mutex<ConnectionSet>>, rx: mpsc::Receiver<ControlEvent>, needs_update: &AtomicBool) -> Result<(), io::Error> {
 
     let stdout = io::stdout().into_raw_mode()?;
     let stdout = AlternateScreen::from(stdout);
@@ -285,9 +333,12 @@ fn display(root_path: Display, connection_set: Arc<Mutex<ConnectionSet>>, rx: mp
                     )
                     .split(f.size());
 
-                let block = List::new(
-                                vec![ListItem::new(vec![Spans::from(Span::raw(format!("Serving {}", root_path)))])]
-                            ).block(Block::default().borders(Borders::ALL).title("Information"));
+                let mut info_lines = vec![ListItem::new(vec![Spans::from(Span::raw(format!("Serving {}", root_path)))])];
+                if let Some(fingerprint) = &tls_fingerprint {
+                    info_lines.push(ListItem::new(vec![Spans::from(Span::raw(format!("TLS fingerprint: {}", fingerprint)))]));
+                }
+                let block = List::new(info_lines)
+                            .block(Block::default().borders(Borders::ALL).title("Information"));
                 f.render_widget(block, chunks[0]);
 
                 let block = List::new(messages).block(Block::default().borders(Borders::ALL).title("Connections"));
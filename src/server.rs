@@ -0,0 +1,1303 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use nix::errno::Errno;
+use nix::sys::select::{select, FdSet};
+use nix::sys::socket::{connect, getsockopt, socket, sockopt, AddressFamily, InetAddr, SockAddr, SockFlag, SockType};
+use nix::sys::time::{TimeVal, TimeValLike};
+
+use rustls::{Certificate, PrivateKey, ServerConfig, ServerConnection, StreamOwned};
+use sha2::{Digest, Sha256};
+
+const READ_CHUNK: usize = 64 * 1024;
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+
+/// How long a proxied request is allowed to spend connecting to, or
+/// waiting on response headers from, an upstream before the client gets a
+/// 502 instead of hanging indefinitely.
+const PROXY_CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+const PROXY_HEADER_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// Per-IP connection cap, plus a little slack so a client reconnecting
+/// (old socket still closing while the new one is accepted) doesn't get
+/// rejected outright.
+const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 8;
+const DEFAULT_OVERLAP_ALLOWANCE: usize = 2;
+
+enum ConnState {
+    ReadingRequest(Vec<u8>),
+    /// A request for a path this connection can't answer on its own (it
+    /// needs server-wide state); `HttpTui::run` fills in the body and moves
+    /// the connection on to `SendingResponse`.
+    PendingMetrics,
+    /// Non-blocking connect to a proxy upstream in progress; `request` is
+    /// written out (from `written` onward) once the socket reports
+    /// writable, which also happens to be the signal that connect()
+    /// finished (successfully or not).
+    ProxyConnecting { upstream: TcpStream, request: Vec<u8>, written: usize, deadline: time::Instant },
+    /// Upstream connected and the request fully sent; its response is
+    /// relayed back to the client one chunk at a time as each side reports
+    /// ready, rather than buffering the whole thing in memory up front.
+    ProxyStreaming {
+        upstream: TcpStream,
+        /// Raw bytes read from `upstream` before the header has been found.
+        raw: Vec<u8>,
+        /// The client-facing response header, once parsed from `raw`.
+        header: Option<Vec<u8>>,
+        header_sent: usize,
+        /// Present only once the upstream response turned out to be chunked.
+        decoder: Option<ChunkedDecoder>,
+        /// Decoded body bytes waiting to be written to the client.
+        pending_body: Vec<u8>,
+        upstream_done: bool,
+        deadline: time::Instant,
+    },
+    /// `remaining` is `Some(n)` when the total response length is known up
+    /// front (files, static bodies) and counts down to zero; it's `None`
+    /// when the body's length isn't known ahead of time, in which case the
+    /// response ends when `body` itself reports EOF.
+    SendingResponse { body: Box<dyn Read>, remaining: Option<usize> },
+    Done,
+}
+
+/// Incrementally strips `Transfer-Encoding: chunked` framing from raw bytes
+/// fed in as they arrive, so a chunked upstream response can be relayed to
+/// the client without buffering the whole body first.
+struct ChunkedDecoder {
+    pending: Vec<u8>,
+    finished: bool,
+}
+
+impl ChunkedDecoder {
+    fn new() -> ChunkedDecoder {
+        ChunkedDecoder { pending: Vec::new(), finished: false }
+    }
+
+    /// Feeds newly-read raw bytes in and returns any body bytes they complete.
+    fn feed(&mut self, raw: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(raw);
+        let mut out = Vec::new();
+        while !self.finished {
+            let line_end = match self.pending.windows(2).position(|w| w == b"\r\n") {
+                Some(p) => p,
+                None => break,
+            };
+            let size = match usize::from_str_radix(String::from_utf8_lossy(&self.pending[..line_end]).trim(), 16) {
+                Ok(s) => s,
+                Err(_) => { self.finished = true; break; }
+            };
+            if size == 0 {
+                self.finished = true;
+                self.pending.clear();
+                break;
+            }
+            let chunk_start = line_end + 2;
+            if self.pending.len() < chunk_start + size + 2 {
+                break;
+            }
+            out.extend_from_slice(&self.pending[chunk_start..chunk_start + size]);
+            self.pending.drain(..chunk_start + size + 2);
+        }
+        out
+    }
+}
+
+/// A token bucket shared by one or more connections. Tokens (bytes) refill
+/// continuously at whatever rate the caller passes to `available`;
+/// callers only ever consume what they observed as available, so there's no
+/// way to go negative.
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    last_refill: time::Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64) -> TokenBucket {
+        TokenBucket { capacity, tokens: capacity, last_refill: time::Instant::now() }
+    }
+
+    fn available(&mut self, rate: f64) -> usize {
+        let now = time::Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * rate).min(self.capacity.max(rate));
+        self.tokens.max(0.0) as usize
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.tokens = (self.tokens - bytes as f64).max(0.0);
+    }
+}
+
+/// How outbound bytes are throttled while streaming a response body.
+#[derive(Clone)]
+enum RateLimitMode {
+    Unlimited,
+    /// Each connection gets its own bucket refilling at this many bytes/sec.
+    PerConnection(f64),
+    /// One shared bucket refilling at the configured total rate; each time
+    /// it's consulted a connection may take at most an equal share (one
+    /// over the number of active connections) of what's in it.
+    Global(Arc<Mutex<TokenBucket>>, f64),
+}
+
+/// Either a plain TCP socket or one wrapped in a TLS session. Both
+/// implement `Read`/`Write` directly so the rest of the connection state
+/// machine doesn't need to know which it's talking to.
+pub(crate) enum Transport {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Transport {
+    pub(crate) fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Transport::Plain(s) => s.peer_addr(),
+            Transport::Tls(s) => s.sock.peer_addr(),
+        }
+    }
+
+    fn is_handshaking(&self) -> bool {
+        match self {
+            Transport::Plain(_) => false,
+            Transport::Tls(s) => s.conn.is_handshaking(),
+        }
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(s) => s.read(buf),
+            Transport::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(s) => s.write(buf),
+            Transport::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Plain(s) => s.flush(),
+            Transport::Tls(s) => s.flush(),
+        }
+    }
+}
+
+pub struct HttpConnection {
+    pub stream: Transport,
+    pub bytes_sent: usize,
+    pub bytes_requested: usize,
+    pub last_requested_uri: Option<String>,
+    state: ConnState,
+    rate_bucket: Option<TokenBucket>,
+    writable_after: time::Instant,
+}
+
+impl HttpConnection {
+    fn new(stream: Transport) -> HttpConnection {
+        HttpConnection {
+            stream,
+            bytes_sent: 0,
+            bytes_requested: 0,
+            last_requested_uri: None,
+            state: ConnState::ReadingRequest(Vec::new()),
+            rate_bucket: None,
+            writable_after: time::Instant::now(),
+        }
+    }
+
+    fn wants_read(&self) -> bool {
+        self.stream.is_handshaking() || matches!(self.state, ConnState::ReadingRequest(_))
+    }
+
+    fn wants_write(&self) -> bool {
+        if self.stream.is_handshaking() {
+            return true;
+        }
+        if time::Instant::now() < self.writable_after {
+            return false;
+        }
+        match &self.state {
+            ConnState::SendingResponse { .. } => true,
+            ConnState::ProxyStreaming { header, header_sent, pending_body, .. } => {
+                matches!(header, Some(h) if *header_sent < h.len()) || !pending_body.is_empty()
+            }
+            _ => false,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        matches!(self.state, ConnState::Done)
+    }
+
+    /// The upstream socket's fd, when this connection is mid-proxy — kept
+    /// out of the client-facing `wants_read`/`wants_write` pair above since
+    /// it's a *different* fd that `HttpTui::run` must add to its own
+    /// `select()` sets.
+    fn extra_fd(&self) -> Option<RawFd> {
+        match &self.state {
+            ConnState::ProxyConnecting { upstream, .. } => Some(upstream.as_raw_fd()),
+            ConnState::ProxyStreaming { upstream, .. } => Some(upstream.as_raw_fd()),
+            _ => None,
+        }
+    }
+
+    fn extra_wants_read(&self) -> bool {
+        matches!(self.state, ConnState::ProxyStreaming { upstream_done: false, .. })
+    }
+
+    fn extra_wants_write(&self) -> bool {
+        matches!(self.state, ConnState::ProxyConnecting { .. })
+    }
+
+    /// Aborts a proxy attempt that's taken too long to connect or to
+    /// produce response headers, so an unreachable or stalled upstream
+    /// can't hang the connection (and, via `extra_fd`, the select loop's
+    /// bookkeeping for it) forever.
+    fn check_proxy_timeout(&mut self) {
+        let expired = match &self.state {
+            ConnState::ProxyConnecting { deadline, .. } => time::Instant::now() >= *deadline,
+            ConnState::ProxyStreaming { header: None, deadline, .. } => time::Instant::now() >= *deadline,
+            _ => false,
+        };
+        if expired {
+            self.fail_proxy();
+        }
+    }
+
+    fn fail_proxy(&mut self) {
+        let body = b"Bad Gateway";
+        let header = format!(
+            "HTTP/1.1 502 Bad Gateway\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        let mut resp = header.into_bytes();
+        resp.extend_from_slice(body);
+        self.bytes_requested = resp.len();
+        let remaining = resp.len();
+        self.state = ConnState::SendingResponse { body: Box::new(io::Cursor::new(resp)), remaining: Some(remaining) };
+    }
+
+    fn on_readable(&mut self, root: &Path, metrics_enabled: bool, proxy_rules: &[ProxyRule]) {
+        let buf = match &mut self.state {
+            ConnState::ReadingRequest(buf) => buf,
+            _ => return,
+        };
+
+        let mut chunk = [0u8; 4096];
+        match self.stream.read(&mut chunk) {
+            Ok(0) => { self.state = ConnState::Done; }
+            Ok(n) => {
+                buf.extend_from_slice(&chunk[..n]);
+                if let Some(pos) = find_header_end(buf) {
+                    let request = buf[..pos].to_vec();
+                    self.begin_response(&request, root, metrics_enabled, proxy_rules);
+                } else if buf.len() > MAX_HEADER_SIZE {
+                    self.state = ConnState::Done;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(_) => { self.state = ConnState::Done; }
+        }
+    }
+
+    fn begin_response(&mut self, request: &[u8], root: &Path, metrics_enabled: bool, proxy_rules: &[ProxyRule]) {
+        let request_text = String::from_utf8_lossy(request).to_string();
+        let request_line = request_text.lines().next().unwrap_or("");
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or("GET").to_string();
+        let uri = parts.next().unwrap_or("/").to_string();
+
+        self.last_requested_uri = Some(uri.clone());
+
+        let uri_path = uri.split('?').next().unwrap_or("/");
+        let rel = uri_path.trim_start_matches('/');
+
+        if metrics_enabled && rel == "metrics" {
+            self.state = ConnState::PendingMetrics;
+            return;
+        }
+
+        if let Some(rule) = match_proxy_rule(proxy_rules, uri_path) {
+            let header_lines: Vec<&str> = request_text.lines().skip(1).filter(|l| !l.is_empty()).collect();
+            self.begin_proxy_response(rule, &method, &uri, &header_lines);
+            return;
+        }
+
+        let path = if rel.is_empty() { root.join("index.html") } else { root.join(rel) };
+
+        match File::open(&path) {
+            Ok(mut file) => {
+                let total_size = file.metadata().map(|m| m.len()).unwrap_or(0);
+
+                match parse_range(&request_text, total_size) {
+                    RangeOutcome::Satisfiable(start, end) => {
+                        let _ = file.seek(SeekFrom::Start(start));
+                        let len = (end - start + 1) as usize;
+                        let header = format!(
+                            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nAccept-Ranges: bytes\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            start, end, total_size, len
+                        ).into_bytes();
+                        self.bytes_requested = header.len() + len;
+                        let remaining = header.len() + len;
+                        self.state = ConnState::SendingResponse {
+                            body: Box::new(io::Cursor::new(header).chain(file)),
+                            remaining: Some(remaining),
+                        };
+                    }
+                    RangeOutcome::NotRequested => {
+                        let size = total_size as usize;
+                        let header = format!(
+                            "HTTP/1.1 200 OK\r\nAccept-Ranges: bytes\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            size
+                        ).into_bytes();
+                        self.bytes_requested = header.len() + size;
+                        let remaining = header.len() + size;
+                        self.state = ConnState::SendingResponse {
+                            body: Box::new(io::Cursor::new(header).chain(file)),
+                            remaining: Some(remaining),
+                        };
+                    }
+                    RangeOutcome::Unsatisfiable => {
+                        let body = b"Range Not Satisfiable";
+                        let header = format!(
+                            "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            total_size, body.len()
+                        );
+                        let mut resp = header.into_bytes();
+                        resp.extend_from_slice(body);
+                        self.bytes_requested = resp.len();
+                        let remaining = resp.len();
+                        self.state = ConnState::SendingResponse { body: Box::new(io::Cursor::new(resp)), remaining: Some(remaining) };
+                    }
+                }
+            }
+            Err(_) => {
+                let body = b"Not Found";
+                let header = format!(
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let mut resp = header.into_bytes();
+                resp.extend_from_slice(body);
+                self.bytes_requested = resp.len();
+                let remaining = resp.len();
+                self.state = ConnState::SendingResponse { body: Box::new(io::Cursor::new(resp)), remaining: Some(remaining) };
+            }
+        }
+    }
+
+    /// Kicks off a non-blocking connect to the upstream named by `rule`.
+    /// The connect and the request/response relay that follows both ride
+    /// the same `select()` loop as every client connection (see
+    /// `extra_fd`/`on_proxy_connect_writable`/`on_proxy_readable`), so a
+    /// slow or unreachable upstream only stalls this one connection.
+    fn begin_proxy_response(&mut self, rule: &ProxyRule, method: &str, uri: &str, header_lines: &[&str]) {
+        let request = build_proxy_request(rule, method, uri, header_lines);
+
+        match connect_nonblocking(rule.upstream_addr) {
+            Ok(upstream) => {
+                self.state = ConnState::ProxyConnecting {
+                    upstream,
+                    request,
+                    written: 0,
+                    deadline: time::Instant::now() + PROXY_CONNECT_TIMEOUT,
+                };
+            }
+            Err(_) => self.fail_proxy(),
+        }
+    }
+
+    /// Drives a `ProxyConnecting` upstream: checks whether the non-blocking
+    /// connect finished (successfully or not), then writes out the request
+    /// a bit at a time as the upstream reports writable.
+    fn on_proxy_connect_writable(&mut self) {
+        let failed = match &self.state {
+            ConnState::ProxyConnecting { upstream, .. } => connect_error(upstream).is_err(),
+            _ => return,
+        };
+        if failed {
+            self.fail_proxy();
+            return;
+        }
+
+        let fully_sent = match &mut self.state {
+            ConnState::ProxyConnecting { upstream, request, written, .. } => {
+                match upstream.write(&request[*written..]) {
+                    Ok(n) => { *written += n; }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(_) => { self.state = ConnState::Done; return; }
+                }
+                *written >= request.len()
+            }
+            _ => return,
+        };
+
+        if fully_sent {
+            if let ConnState::ProxyConnecting { upstream, .. } = std::mem::replace(&mut self.state, ConnState::Done) {
+                self.state = ConnState::ProxyStreaming {
+                    upstream,
+                    raw: Vec::new(),
+                    header: None,
+                    header_sent: 0,
+                    decoder: None,
+                    pending_body: Vec::new(),
+                    upstream_done: false,
+                    deadline: time::Instant::now() + PROXY_HEADER_TIMEOUT,
+                };
+            }
+        }
+    }
+
+    /// Reads whatever the upstream has ready, decoding chunked framing
+    /// incrementally and parsing the response header out of the first
+    /// chunk(s) that contain it.
+    fn on_proxy_readable(&mut self) {
+        let mut newly_known_length: Option<u64> = None;
+
+        if let ConnState::ProxyStreaming { upstream, raw, header, decoder, pending_body, upstream_done, .. } = &mut self.state {
+            let mut chunk = [0u8; READ_CHUNK];
+            match upstream.read(&mut chunk) {
+                Ok(0) => { *upstream_done = true; }
+                Ok(n) => {
+                    if header.is_none() {
+                        raw.extend_from_slice(&chunk[..n]);
+                        if let Some(pos) = find_header_end(raw) {
+                            let header_text = String::from_utf8_lossy(&raw[..pos]).to_string();
+                            let body_so_far = raw[pos..].to_vec();
+                            let (client_header, chunked, content_length) = build_proxy_response_header(&header_text);
+                            newly_known_length = content_length;
+                            *header = Some(client_header);
+                            if chunked {
+                                let mut d = ChunkedDecoder::new();
+                                pending_body.extend(d.feed(&body_so_far));
+                                *decoder = Some(d);
+                            } else {
+                                pending_body.extend(body_so_far);
+                            }
+                        } else if raw.len() > MAX_HEADER_SIZE {
+                            *upstream_done = true;
+                        }
+                    } else if let Some(d) = decoder {
+                        pending_body.extend(d.feed(&chunk[..n]));
+                        if d.finished { *upstream_done = true; }
+                    } else {
+                        pending_body.extend_from_slice(&chunk[..n]);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(_) => { *upstream_done = true; }
+            }
+        }
+
+        if let Some(len) = newly_known_length {
+            self.bytes_requested = len as usize;
+        }
+    }
+
+    /// Writes buffered proxy response bytes (header first, then decoded
+    /// body) to the client, respecting the same rate limit as any other
+    /// response.
+    fn on_proxy_client_writable(&mut self, rate_limit: &RateLimitMode, active_connections: usize) {
+        let (header, header_sent, pending_body, upstream_done) = match &mut self.state {
+            ConnState::ProxyStreaming { header, header_sent, pending_body, upstream_done, .. } => {
+                (header, header_sent, pending_body, upstream_done)
+            }
+            _ => return,
+        };
+
+        let sending_header = matches!(header, Some(h) if *header_sent < h.len());
+        let remaining_len = if sending_header {
+            header.as_ref().unwrap().len() - *header_sent
+        } else {
+            pending_body.len()
+        };
+
+        if remaining_len == 0 {
+            if *upstream_done && !sending_header {
+                self.state = ConnState::Done;
+            }
+            return;
+        }
+
+        let cap = remaining_len.min(READ_CHUNK);
+        let allowed = rate_allowance(&mut self.rate_bucket, &mut self.writable_after, rate_limit, active_connections, cap);
+        if allowed == 0 {
+            return;
+        }
+
+        let (header, header_sent, pending_body, upstream_done) = match &mut self.state {
+            ConnState::ProxyStreaming { header, header_sent, pending_body, upstream_done, .. } => {
+                (header, header_sent, pending_body, upstream_done)
+            }
+            _ => return,
+        };
+
+        let buf: Vec<u8> = if sending_header {
+            let h = header.as_ref().unwrap();
+            h[*header_sent..*header_sent + allowed].to_vec()
+        } else {
+            pending_body[..allowed].to_vec()
+        };
+
+        match self.stream.write(&buf) {
+            Ok(sent) => {
+                self.bytes_sent += sent;
+                consume_rate(&mut self.rate_bucket, rate_limit, sent);
+                if sending_header {
+                    *header_sent += sent;
+                } else {
+                    pending_body.drain(..sent);
+                }
+                if *upstream_done && pending_body.is_empty() && header.as_ref().map_or(true, |h| *header_sent >= h.len()) {
+                    self.state = ConnState::Done;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(_) => { self.state = ConnState::Done; }
+        }
+    }
+
+    /// Called by `HttpTui::run` once per tick for connections stuck in
+    /// `PendingMetrics`, with the scrape text rendered from server-wide state.
+    fn serve_text(&mut self, body: String) {
+        let header = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        );
+        let mut resp = header.into_bytes();
+        resp.extend_from_slice(body.as_bytes());
+        self.bytes_requested = resp.len();
+        let remaining = resp.len();
+        self.state = ConnState::SendingResponse { body: Box::new(io::Cursor::new(resp)), remaining: Some(remaining) };
+    }
+
+    fn on_writable(&mut self, rate_limit: &RateLimitMode, active_connections: usize) {
+        // `wants_write` asks select() for write-readiness during a TLS
+        // handshake so an outbound handshake write that would otherwise
+        // block (big cert chain, congested link) still gets driven to
+        // completion; do that here rather than leaving write-readiness
+        // unacted-on and just spinning select().
+        if self.stream.is_handshaking() {
+            let _ = self.stream.write(&[]);
+            return;
+        }
+
+        if matches!(self.state, ConnState::ProxyStreaming { .. }) {
+            self.on_proxy_client_writable(rate_limit, active_connections);
+            return;
+        }
+
+        let (body, remaining) = match &mut self.state {
+            ConnState::SendingResponse { body, remaining } => (body, remaining),
+            _ => return,
+        };
+
+        if *remaining == Some(0) {
+            self.state = ConnState::Done;
+            return;
+        }
+
+        let cap = match remaining {
+            Some(r) => READ_CHUNK.min(*r),
+            None => READ_CHUNK,
+        };
+        let allowed = rate_allowance(&mut self.rate_bucket, &mut self.writable_after, rate_limit, active_connections, cap);
+        if allowed == 0 {
+            return;
+        }
+
+        let mut buf = vec![0u8; allowed];
+        match body.read(&mut buf) {
+            Ok(0) => { self.state = ConnState::Done; }
+            Ok(n) => match self.stream.write(&buf[..n]) {
+                Ok(sent) => {
+                    self.bytes_sent += sent;
+                    if let Some(r) = remaining { *r -= sent; }
+                    consume_rate(&mut self.rate_bucket, rate_limit, sent);
+                    if *remaining == Some(0) {
+                        self.state = ConnState::Done;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(_) => { self.state = ConnState::Done; }
+            },
+            // A WouldBlock here means the body source (e.g. a non-blocking
+            // upstream proxy socket) just doesn't have more data yet, not
+            // that the response failed.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(_) => { self.state = ConnState::Done; }
+        }
+    }
+}
+
+/// Returns how many of `max` bytes may be sent right now under `rate_limit`,
+/// without consuming them yet (see `consume_rate`). Returns 0 — and pushes
+/// `writable_after` out — once the budget is exhausted.
+fn rate_allowance(
+    bucket: &mut Option<TokenBucket>,
+    writable_after: &mut time::Instant,
+    rate_limit: &RateLimitMode,
+    active_connections: usize,
+    max: usize,
+) -> usize {
+    match rate_limit {
+        RateLimitMode::Unlimited => max,
+        RateLimitMode::PerConnection(rate) => {
+            let avail = bucket.get_or_insert_with(|| TokenBucket::new(*rate)).available(*rate);
+            if avail == 0 {
+                *writable_after = time::Instant::now() + time::Duration::from_secs_f64(1.0 / rate.max(1.0));
+                return 0;
+            }
+            avail.min(max)
+        }
+        RateLimitMode::Global(shared, total_rate) => {
+            let effective_rate = total_rate / (active_connections.max(1) as f64);
+            let avail = shared.lock().unwrap().available(*total_rate) / active_connections.max(1);
+            if avail == 0 {
+                *writable_after = time::Instant::now() + time::Duration::from_secs_f64(1.0 / effective_rate.max(1.0));
+                return 0;
+            }
+            avail.min(max)
+        }
+    }
+}
+
+fn consume_rate(bucket: &mut Option<TokenBucket>, rate_limit: &RateLimitMode, sent: usize) {
+    match rate_limit {
+        RateLimitMode::Unlimited => {}
+        RateLimitMode::PerConnection(_) => { if let Some(b) = bucket { b.consume(sent); } }
+        RateLimitMode::Global(shared, _) => { shared.lock().unwrap().consume(sent); }
+    }
+}
+
+fn find_header_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n").map(|p| p + 4)
+}
+
+/// Result of looking for a `Range` header: no header present at all (serve
+/// the full body), a satisfiable inclusive `(start, end)` byte range, or a
+/// header that was present but unsatisfiable against `file_size` (start past
+/// EOF, empty suffix, or `start > end`), which should get a 416 rather than
+/// silently falling back to a full 200 response.
+#[derive(Debug, PartialEq, Eq)]
+enum RangeOutcome {
+    NotRequested,
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parses a single `Range: bytes=start-end` (or `start-` / `-suffix_len`)
+/// header into an inclusive `(start, end)` byte range. `If-Range` is not
+/// validated against an ETag/Last-Modified since this server doesn't track
+/// either, so any `Range` present is honored unconditionally.
+fn parse_range(request_text: &str, file_size: u64) -> RangeOutcome {
+    let range_line = match request_text.lines().find(|l| l.to_ascii_lowercase().starts_with("range:")) {
+        Some(line) => line,
+        None => return RangeOutcome::NotRequested,
+    };
+    // A syntactically malformed Range header is ignored rather than
+    // rejected, per RFC 7233 §3.1; only a well-formed but out-of-bounds
+    // range is reported as unsatisfiable.
+    let spec = match range_line.splitn(2, ':').nth(1).map(|s| s.trim()).and_then(|s| s.strip_prefix("bytes=")) {
+        Some(spec) => spec,
+        None => return RangeOutcome::NotRequested,
+    };
+    let (start_str, end_str) = match spec.split_once('-') {
+        Some(parts) => parts,
+        None => return RangeOutcome::NotRequested,
+    };
+
+    if start_str.is_empty() {
+        let suffix_len: u64 = match end_str.parse() {
+            Ok(n) => n,
+            Err(_) => return RangeOutcome::NotRequested,
+        };
+        if suffix_len == 0 || file_size == 0 {
+            return RangeOutcome::Unsatisfiable;
+        }
+        return RangeOutcome::Satisfiable(file_size.saturating_sub(suffix_len), file_size - 1);
+    }
+
+    let start: u64 = match start_str.parse() {
+        Ok(n) => n,
+        Err(_) => return RangeOutcome::NotRequested,
+    };
+    let end: u64 = match if end_str.is_empty() { Ok(file_size.saturating_sub(1)) } else { end_str.parse() } {
+        Ok(n) => n,
+        Err(_) => return RangeOutcome::NotRequested,
+    };
+
+    if file_size == 0 || start > end || start >= file_size {
+        return RangeOutcome::Unsatisfiable;
+    }
+    RangeOutcome::Satisfiable(start, end.min(file_size - 1))
+}
+
+#[cfg(test)]
+mod range_tests {
+    use super::*;
+
+    #[test]
+    fn no_range_header_is_not_requested() {
+        assert_eq!(parse_range("GET / HTTP/1.1\r\n\r\n", 100), RangeOutcome::NotRequested);
+    }
+
+    #[test]
+    fn plain_start_end_range() {
+        assert_eq!(parse_range("GET / HTTP/1.1\r\nRange: bytes=0-49\r\n\r\n", 100), RangeOutcome::Satisfiable(0, 49));
+    }
+
+    #[test]
+    fn open_ended_range_clamps_to_last_byte() {
+        assert_eq!(parse_range("GET / HTTP/1.1\r\nRange: bytes=90-\r\n\r\n", 100), RangeOutcome::Satisfiable(90, 99));
+    }
+
+    #[test]
+    fn suffix_range_from_end() {
+        assert_eq!(parse_range("GET / HTTP/1.1\r\nRange: bytes=-10\r\n\r\n", 100), RangeOutcome::Satisfiable(90, 99));
+    }
+
+    #[test]
+    fn start_past_eof_is_unsatisfiable() {
+        assert_eq!(parse_range("GET / HTTP/1.1\r\nRange: bytes=100-149\r\n\r\n", 100), RangeOutcome::Unsatisfiable);
+    }
+
+    #[test]
+    fn start_after_end_is_unsatisfiable() {
+        assert_eq!(parse_range("GET / HTTP/1.1\r\nRange: bytes=50-10\r\n\r\n", 100), RangeOutcome::Unsatisfiable);
+    }
+
+    #[test]
+    fn zero_length_suffix_is_unsatisfiable() {
+        assert_eq!(parse_range("GET / HTTP/1.1\r\nRange: bytes=-0\r\n\r\n", 100), RangeOutcome::Unsatisfiable);
+    }
+
+    #[test]
+    fn malformed_range_header_is_ignored() {
+        assert_eq!(parse_range("GET / HTTP/1.1\r\nRange: nonsense\r\n\r\n", 100), RangeOutcome::NotRequested);
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ProxyRule {
+    prefix: String,
+    upstream_host: String,
+    upstream_addr: SocketAddr,
+}
+
+/// Parses a `<prefix>=<upstream-url>` flag value, e.g. `/api=http://localhost:4000`,
+/// resolving the upstream's address once up front so handling a proxied
+/// request never has to do DNS resolution (which blocks) from inside the
+/// select loop.
+pub(crate) fn parse_proxy_rule(spec: &str) -> Option<ProxyRule> {
+    let (prefix, url) = spec.split_once('=')?;
+    let rest = url.strip_prefix("http://")?;
+    let rest = rest.trim_end_matches('/');
+    let (host, port) = match rest.split_once(':') {
+        Some((h, p)) => (h.to_string(), p.parse().ok()?),
+        None => (rest.to_string(), 80),
+    };
+    let upstream_addr = (host.as_str(), port).to_socket_addrs().ok()?.next()?;
+    Some(ProxyRule { prefix: prefix.to_string(), upstream_host: host, upstream_addr })
+}
+
+/// Picks the longest matching prefix so more specific rules win over broader ones.
+fn match_proxy_rule<'a>(rules: &'a [ProxyRule], uri_path: &str) -> Option<&'a ProxyRule> {
+    rules.iter()
+        .filter(|r| uri_path.starts_with(r.prefix.as_str()))
+        .max_by_key(|r| r.prefix.len())
+}
+
+#[cfg(test)]
+mod proxy_rule_tests {
+    use super::*;
+
+    #[test]
+    fn rejects_spec_without_equals() {
+        assert!(parse_proxy_rule("http://127.0.0.1:4000").is_none());
+    }
+
+    #[test]
+    fn rejects_non_http_scheme() {
+        assert!(parse_proxy_rule("/api=https://127.0.0.1:4000").is_none());
+    }
+
+    #[test]
+    fn defaults_to_port_80() {
+        let rule = parse_proxy_rule("/api=http://127.0.0.1").unwrap();
+        assert_eq!(rule.upstream_addr, "127.0.0.1:80".parse().unwrap());
+    }
+
+    #[test]
+    fn parses_explicit_port_and_trims_trailing_slash() {
+        let rule = parse_proxy_rule("/api=http://127.0.0.1:4000/").unwrap();
+        assert_eq!(rule.prefix, "/api");
+        assert_eq!(rule.upstream_addr, "127.0.0.1:4000".parse().unwrap());
+    }
+
+    #[test]
+    fn rejects_unresolvable_port() {
+        assert!(parse_proxy_rule("/api=http://127.0.0.1:notaport").is_none());
+    }
+
+    #[test]
+    fn match_picks_longest_matching_prefix() {
+        let rules = vec![
+            parse_proxy_rule("/api=http://127.0.0.1:4000").unwrap(),
+            parse_proxy_rule("/api/v2=http://127.0.0.1:5000").unwrap(),
+        ];
+        let matched = match_proxy_rule(&rules, "/api/v2/users").unwrap();
+        assert_eq!(matched.upstream_addr, "127.0.0.1:5000".parse().unwrap());
+    }
+
+    #[test]
+    fn match_returns_none_when_no_prefix_fits() {
+        let rules = vec![parse_proxy_rule("/api=http://127.0.0.1:4000").unwrap()];
+        assert!(match_proxy_rule(&rules, "/static/index.html").is_none());
+    }
+}
+
+/// Builds the raw request bytes to send to a proxy upstream, dropping the
+/// client's `Host`/`Connection` headers in favor of ones naming the
+/// upstream directly.
+fn build_proxy_request(rule: &ProxyRule, method: &str, uri: &str, header_lines: &[&str]) -> Vec<u8> {
+    let mut req = format!("{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", method, uri, rule.upstream_host);
+    for line in header_lines {
+        let lower = line.to_ascii_lowercase();
+        if lower.starts_with("host:") || lower.starts_with("connection:") {
+            continue;
+        }
+        req.push_str(line);
+        req.push_str("\r\n");
+    }
+    req.push_str("\r\n");
+    req.into_bytes()
+}
+
+/// Headers that describe the hop between us and the upstream rather than
+/// the content of the response, so they're dropped instead of forwarded:
+/// we always speak `Connection: close` to the client regardless of what
+/// the upstream did, and bodies are relayed already-dechunked.
+const HOP_BY_HOP_HEADERS: &[&str] = &["connection", "transfer-encoding", "keep-alive", "proxy-connection", "upgrade", "trailer", "te"];
+
+/// Parses an upstream response header into the header bytes to forward to
+/// the client, whether the body is chunked, and its length when known up
+/// front (chunked bodies have no declared length). Every upstream header
+/// is forwarded as-is (Set-Cookie, Location, caching headers, etc.) except
+/// the hop-by-hop ones, which only make sense between us and the upstream.
+fn build_proxy_response_header(header_text: &str) -> (Vec<u8>, bool, Option<u64>) {
+    let status_line = header_text.lines().next().unwrap_or("HTTP/1.1 502 Bad Gateway").trim();
+    let chunked = header_text.to_ascii_lowercase().contains("transfer-encoding: chunked");
+
+    let mut out = format!("{}\r\n", status_line);
+    let mut content_length = None;
+    for line in header_text.lines().skip(1) {
+        let line = line.trim();
+        let name = match line.split_once(':') {
+            Some((name, _)) => name.trim().to_ascii_lowercase(),
+            None => continue,
+        };
+        if HOP_BY_HOP_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        if name == "content-length" {
+            content_length = line.splitn(2, ':').nth(1).and_then(|v| v.trim().parse::<u64>().ok());
+        }
+        out.push_str(line);
+        out.push_str("\r\n");
+    }
+    if chunked {
+        content_length = None;
+    }
+    out.push_str("Connection: close\r\n\r\n");
+    (out.into_bytes(), chunked, content_length)
+}
+
+/// Opens a non-blocking socket and starts connecting it to `addr`,
+/// returning immediately (the connect completes asynchronously, signaled
+/// by the fd becoming writable — see `connect_error`).
+fn connect_nonblocking(addr: SocketAddr) -> io::Result<TcpStream> {
+    let family = if addr.is_ipv4() { AddressFamily::Inet } else { AddressFamily::Inet6 };
+    let fd = socket(family, SockType::Stream, SockFlag::SOCK_NONBLOCK, None)
+        .map_err(nix_to_io_error)?;
+
+    let sock_addr = SockAddr::new_inet(InetAddr::from_std(&addr));
+    match connect(fd, &sock_addr) {
+        Ok(_) | Err(nix::Error::Sys(Errno::EINPROGRESS)) => Ok(unsafe { TcpStream::from_raw_fd(fd) }),
+        Err(e) => {
+            let _ = nix::unistd::close(fd);
+            Err(nix_to_io_error(e))
+        }
+    }
+}
+
+/// `nix::Error`'s `Sys` variant carries the underlying `Errno`, which is what
+/// `io::Error::from_raw_os_error` wants; anything else (a path/UTF-8/platform
+/// error from the `nix` wrapper itself, not the syscall) has no OS error code
+/// to preserve, so it's reported via its `Display` impl instead.
+fn nix_to_io_error(e: nix::Error) -> io::Error {
+    match e {
+        nix::Error::Sys(errno) => io::Error::from_raw_os_error(errno as i32),
+        e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+    }
+}
+
+/// Checks whether a non-blocking connect (see `connect_nonblocking`)
+/// finished successfully once its fd reports writable.
+fn connect_error(stream: &TcpStream) -> io::Result<()> {
+    match getsockopt(stream.as_raw_fd(), sockopt::SocketError) {
+        Ok(0) => Ok(()),
+        Ok(errno) => Err(io::Error::from_raw_os_error(errno)),
+        Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+    }
+}
+
+#[derive(Clone)]
+enum TlsMode {
+    Disabled,
+    Enabled(Arc<ServerConfig>),
+}
+
+fn fingerprint_sha256(der: &[u8]) -> String {
+    Sha256::digest(der).iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":")
+}
+
+/// Generate an ephemeral self-signed certificate so `--tls` works with zero
+/// configuration, the same way the tool picks reasonable defaults for
+/// everything else.
+fn generate_self_signed_cert() -> io::Result<(Vec<Certificate>, PrivateKey, String)> {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    let der = cert.serialize_der().map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    let key_der = cert.serialize_private_key_der();
+    let fingerprint = fingerprint_sha256(&der);
+    Ok((vec![Certificate(der)], PrivateKey(key_der), fingerprint))
+}
+
+fn load_cert_and_key(cert_path: &Path, key_path: &Path) -> io::Result<(Vec<Certificate>, PrivateKey, String)> {
+    let cert_bytes = std::fs::read(cert_path)?;
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut cert_bytes.as_slice())?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    let fingerprint = certs.first().map(|c| fingerprint_sha256(&c.0)).unwrap_or_default();
+
+    let key_bytes = std::fs::read(key_path)?;
+    let key = rustls_pemfile::pkcs8_private_keys(&mut key_bytes.as_slice())?
+        .into_iter()
+        .next()
+        .map(PrivateKey)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no private key found in --key file"))?;
+
+    Ok((certs, key, fingerprint))
+}
+
+/// Running totals for connections that have already closed. Combined with
+/// the live connection set at scrape time to render the `/metrics` text.
+#[derive(Default)]
+struct Metrics {
+    requests_total: u64,
+    bytes_sent_total: u64,
+    requests_by_ip: HashMap<IpAddr, u64>,
+    bytes_sent_by_ip: HashMap<IpAddr, u64>,
+    /// Total bytes sent and wall-clock time as of the last `/metrics` scrape,
+    /// used to derive an aggregate throughput gauge between scrapes.
+    last_throughput_sample: Option<(time::Instant, u64)>,
+}
+
+impl Metrics {
+    fn record_closed(&mut self, ip: IpAddr, bytes_sent: usize, served_request: bool) {
+        self.bytes_sent_total += bytes_sent as u64;
+        *self.bytes_sent_by_ip.entry(ip).or_insert(0) += bytes_sent as u64;
+        if served_request {
+            self.requests_total += 1;
+            *self.requests_by_ip.entry(ip).or_insert(0) += 1;
+        }
+    }
+}
+
+pub struct HttpTui {
+    listener: TcpListener,
+    root: PathBuf,
+    connections: HashMap<i32, HttpConnection>,
+    conns_per_ip: HashMap<IpAddr, usize>,
+    max_connections_per_ip: usize,
+    overlap_allowance: usize,
+    rate_limit: RateLimitMode,
+    metrics_enabled: bool,
+    metrics: Metrics,
+    tls: TlsMode,
+    tls_fingerprint: Option<String>,
+    proxy_rules: Vec<ProxyRule>,
+}
+
+impl HttpTui {
+    pub fn new(host: &str, port: u16, root: &Path) -> io::Result<HttpTui> {
+        let listener = TcpListener::bind((host, port))?;
+        listener.set_nonblocking(true)?;
+        Ok(HttpTui {
+            listener,
+            root: root.to_path_buf(),
+            connections: HashMap::new(),
+            conns_per_ip: HashMap::new(),
+            max_connections_per_ip: DEFAULT_MAX_CONNECTIONS_PER_IP,
+            overlap_allowance: DEFAULT_OVERLAP_ALLOWANCE,
+            rate_limit: RateLimitMode::Unlimited,
+            metrics_enabled: false,
+            metrics: Metrics::default(),
+            tls: TlsMode::Disabled,
+            tls_fingerprint: None,
+            proxy_rules: Vec::new(),
+        })
+    }
+
+    /// Registers a `<prefix>=<upstream-url>` forwarding rule; requests whose
+    /// path starts with `prefix` are proxied to the upstream instead of
+    /// being served from `root`.
+    pub fn with_proxy_rule(mut self, rule: ProxyRule) -> HttpTui {
+        self.proxy_rules.push(rule);
+        self
+    }
+
+    /// Enable TLS for accepted connections. If `cert_path`/`key_path` are
+    /// both given they're loaded from disk; otherwise an ephemeral
+    /// self-signed certificate is generated so `https://` works out of the box.
+    pub fn with_tls(mut self, cert_path: Option<&Path>, key_path: Option<&Path>) -> io::Result<HttpTui> {
+        let (certs, key, fingerprint) = match (cert_path, key_path) {
+            (Some(cert_path), Some(key_path)) => load_cert_and_key(cert_path, key_path)?,
+            _ => generate_self_signed_cert()?,
+        };
+
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+        self.tls = TlsMode::Enabled(Arc::new(config));
+        self.tls_fingerprint = Some(fingerprint);
+        Ok(self)
+    }
+
+    /// SHA-256 fingerprint of the active certificate, for display in the TUI.
+    pub fn tls_fingerprint(&self) -> Option<&str> {
+        self.tls_fingerprint.as_deref()
+    }
+
+    /// Serve a Prometheus text-format scrape at `GET /metrics`.
+    pub fn with_metrics_enabled(mut self, enabled: bool) -> HttpTui {
+        self.metrics_enabled = enabled;
+        self
+    }
+
+    fn render_metrics(&mut self) -> String {
+        let mut bytes_sent_by_ip = self.metrics.bytes_sent_by_ip.clone();
+        let mut bytes_sent_total = self.metrics.bytes_sent_total;
+
+        for conn in self.connections.values() {
+            if let Ok(addr) = conn.stream.peer_addr() {
+                let ip = addr.ip();
+                *bytes_sent_by_ip.entry(ip).or_insert(0) += conn.bytes_sent as u64;
+                bytes_sent_total += conn.bytes_sent as u64;
+            }
+        }
+
+        let now = time::Instant::now();
+        let throughput = match self.metrics.last_throughput_sample {
+            Some((prev_time, prev_total)) => {
+                let elapsed = now.duration_since(prev_time).as_secs_f64();
+                if elapsed > 0.0 { bytes_sent_total.saturating_sub(prev_total) as f64 / elapsed } else { 0.0 }
+            }
+            None => 0.0,
+        };
+        self.metrics.last_throughput_sample = Some((now, bytes_sent_total));
+
+        let mut out = String::new();
+        out.push_str("# HELP http_tui_bytes_sent_total Total bytes sent to clients.\n");
+        out.push_str("# TYPE http_tui_bytes_sent_total counter\n");
+        for (ip, bytes) in &bytes_sent_by_ip {
+            out.push_str(&format!("http_tui_bytes_sent_total{{remote_ip=\"{}\"}} {}\n", ip, bytes));
+        }
+        out.push_str(&format!("http_tui_bytes_sent_total {}\n", bytes_sent_total));
+
+        out.push_str("# HELP http_tui_requests_total Total number of requests served.\n");
+        out.push_str("# TYPE http_tui_requests_total counter\n");
+        for (ip, count) in &self.metrics.requests_by_ip {
+            out.push_str(&format!("http_tui_requests_total{{remote_ip=\"{}\"}} {}\n", ip, count));
+        }
+        out.push_str(&format!("http_tui_requests_total {}\n", self.metrics.requests_total));
+
+        out.push_str("# HELP http_tui_active_connections Currently open connections.\n");
+        out.push_str("# TYPE http_tui_active_connections gauge\n");
+        for (ip, count) in &self.conns_per_ip {
+            out.push_str(&format!("http_tui_active_connections{{remote_ip=\"{}\"}} {}\n", ip, count));
+        }
+        out.push_str(&format!("http_tui_active_connections {}\n", self.connections.len()));
+
+        out.push_str("# HELP http_tui_throughput_bytes_per_second Aggregate outbound throughput since the last scrape.\n");
+        out.push_str("# TYPE http_tui_throughput_bytes_per_second gauge\n");
+        out.push_str(&format!("http_tui_throughput_bytes_per_second {}\n", throughput));
+
+        out
+    }
+
+    pub fn with_max_connections_per_ip(mut self, max: usize) -> HttpTui {
+        self.max_connections_per_ip = max;
+        self
+    }
+
+    /// `rate` is in bytes/sec; 0 means unlimited. When `global` is set the
+    /// rate is a single shared budget split across all active connections
+    /// rather than a per-connection allowance.
+    pub fn with_rate_limit(mut self, rate: u64, global: bool) -> HttpTui {
+        self.rate_limit = if rate == 0 {
+            RateLimitMode::Unlimited
+        } else if global {
+            RateLimitMode::Global(Arc::new(Mutex::new(TokenBucket::new(rate as f64))), rate as f64)
+        } else {
+            RateLimitMode::PerConnection(rate as f64)
+        };
+        self
+    }
+
+    fn accept_new_connections(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, addr)) => self.accept_one(stream, addr),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => break,
+            }
+        }
+    }
+
+    fn accept_one(&mut self, stream: TcpStream, addr: SocketAddr) {
+        let ip = addr.ip();
+        let cap = self.max_connections_per_ip + self.overlap_allowance;
+        let count = self.conns_per_ip.get(&ip).copied().unwrap_or(0);
+
+        if count >= cap {
+            reject_with_503(stream);
+            return;
+        }
+
+        let _ = stream.set_nonblocking(true);
+        let fd = stream.as_raw_fd();
+
+        let transport = match &self.tls {
+            TlsMode::Disabled => Transport::Plain(stream),
+            TlsMode::Enabled(config) => match ServerConnection::new(config.clone()) {
+                Ok(conn) => Transport::Tls(Box::new(StreamOwned::new(conn, stream))),
+                Err(_) => return,
+            },
+        };
+
+        self.connections.insert(fd, HttpConnection::new(transport));
+        *self.conns_per_ip.entry(ip).or_insert(0) += 1;
+    }
+
+    fn remove_connection(&mut self, fd: i32) {
+        if let Some(conn) = self.connections.remove(&fd) {
+            if let Ok(addr) = conn.stream.peer_addr() {
+                let ip = addr.ip();
+                if let Some(count) = self.conns_per_ip.get_mut(&ip) {
+                    *count = count.saturating_sub(1);
+                    if *count == 0 {
+                        self.conns_per_ip.remove(&ip);
+                    }
+                }
+                self.metrics.record_closed(ip, conn.bytes_sent, conn.last_requested_uri.is_some());
+            }
+        }
+    }
+
+    pub fn run<F: FnMut(&HashMap<i32, HttpConnection>, &HashMap<IpAddr, usize>)>(&mut self, control_fd: RawFd, mut on_update: F) {
+        loop {
+            self.accept_new_connections();
+
+            let mut read_fds = FdSet::new();
+            let mut write_fds = FdSet::new();
+            read_fds.insert(self.listener.as_raw_fd());
+            read_fds.insert(control_fd);
+            for (fd, conn) in self.connections.iter() {
+                if conn.wants_read() { read_fds.insert(*fd); }
+                if conn.wants_write() { write_fds.insert(*fd); }
+                if let Some(extra_fd) = conn.extra_fd() {
+                    if conn.extra_wants_read() { read_fds.insert(extra_fd); }
+                    if conn.extra_wants_write() { write_fds.insert(extra_fd); }
+                }
+            }
+
+            let mut timeout = TimeVal::milliseconds(100);
+            let _ = select(None, &mut read_fds, &mut write_fds, None, &mut timeout);
+
+            if read_fds.contains(control_fd) {
+                return;
+            }
+
+            let rate_limit = self.rate_limit.clone();
+            let active_connections = self.connections.len();
+            let metrics_enabled = self.metrics_enabled;
+            let metrics_pending = metrics_enabled
+                && self.connections.values().any(|c| matches!(c.state, ConnState::PendingMetrics));
+            let metrics_text = if metrics_pending { Some(self.render_metrics()) } else { None };
+
+            let mut finished = Vec::new();
+            for (fd, conn) in self.connections.iter_mut() {
+                if read_fds.contains(*fd) {
+                    conn.on_readable(&self.root, metrics_enabled, &self.proxy_rules);
+                }
+                if matches!(conn.state, ConnState::PendingMetrics) {
+                    if let Some(text) = &metrics_text {
+                        conn.serve_text(text.clone());
+                    }
+                }
+                if let Some(extra_fd) = conn.extra_fd() {
+                    if write_fds.contains(extra_fd) {
+                        conn.on_proxy_connect_writable();
+                    }
+                    if read_fds.contains(extra_fd) {
+                        conn.on_proxy_readable();
+                    }
+                }
+                conn.check_proxy_timeout();
+                if write_fds.contains(*fd) {
+                    conn.on_writable(&rate_limit, active_connections);
+                }
+                if conn.is_done() {
+                    finished.push(*fd);
+                }
+            }
+
+            for fd in finished {
+                self.remove_connection(fd);
+            }
+
+            on_update(&self.connections, &self.conns_per_ip);
+        }
+    }
+}
+
+fn reject_with_503(mut stream: TcpStream) {
+    let body = b"Too many connections from this address";
+    let header = format!(
+        "HTTP/1.1 503 Service Unavailable\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    let _ = stream.write_all(header.as_bytes());
+    let _ = stream.write_all(body);
+}